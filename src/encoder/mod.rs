@@ -18,7 +18,9 @@ use std::io::Write;
 
 mod text;
 mod pb;
+mod openmetrics;
 
+pub use self::openmetrics::{OpenMetricsEncoder, OPENMETRICS_FORMAT};
 pub use self::pb::{ProtobufEncoder, PROTOBUF_FORMAT};
 pub use self::text::{TextEncoder, TEXT_FORMAT};
 
@@ -61,6 +63,7 @@ mod tests {
         let encoders: Vec<Box<Encoder<Output = Vec<u8>>>> = vec![
             Box::new(ProtobufEncoder::new()),
             Box::new(TextEncoder::new()),
+            Box::new(OpenMetricsEncoder::new()),
         ];
         let cv = CounterVec::new(
             Opts::new("test_counter_vec", "help information"),
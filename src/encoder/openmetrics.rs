@@ -0,0 +1,405 @@
+// Copyright 2014 The Prometheus Authors
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::Write;
+
+use proto::{LabelPair, Metric, MetricFamily, MetricType};
+
+use super::check_metric_family;
+use errors::Result;
+use super::Encoder;
+
+/// The content-type for metrics exposed in the OpenMetrics text format,
+/// as required by the spec's content negotiation.
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+const TOTAL_SUFFIX: &str = "_total";
+
+// Suffix-based unit inference, mirroring the Go client's `expfmt` OpenMetrics
+// writer: a family whose name ends in `_<unit>` gets a `# UNIT <unit>` line.
+const KNOWN_UNITS: &[&str] = &[
+    "seconds", "bytes", "ratio", "volts", "amperes", "joules", "grams", "meters", "celsius", "hertz",
+];
+
+fn detect_unit(name: &str) -> Option<&'static str> {
+    KNOWN_UNITS.iter().cloned().find(|unit| {
+        name.len() > unit.len() + 1 && name.ends_with(unit)
+            && name.as_bytes()[name.len() - unit.len() - 1] == b'_'
+    })
+}
+
+/// `OpenMetricsEncoder` encodes metrics in the OpenMetrics text exposition
+/// format, see https://github.com/OpenObservability/OpenMetrics.
+#[derive(Debug, Default)]
+pub struct OpenMetricsEncoder;
+
+impl OpenMetricsEncoder {
+    /// `new` creates a new `OpenMetricsEncoder`.
+    pub fn new() -> OpenMetricsEncoder {
+        OpenMetricsEncoder
+    }
+}
+
+impl Encoder for OpenMetricsEncoder {
+    type Output = Vec<u8>;
+
+    fn encode(&self, mfs: &[MetricFamily], writer: &mut Vec<u8>) -> Result<()> {
+        for mf in mfs {
+            check_metric_family(mf)?;
+            write_metric_family(writer, mf)?;
+        }
+
+        writer.write_all(b"# EOF\n")?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+fn write_metric_family(writer: &mut Vec<u8>, mf: &MetricFamily) -> Result<()> {
+    let metric_type = mf.get_field_type();
+    let name = strip_total_suffix(mf.get_name(), metric_type);
+
+    writer.write_all(b"# TYPE ")?;
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(b" ")?;
+    writer.write_all(type_str(metric_type).as_bytes())?;
+    writer.write_all(b"\n")?;
+
+    if let Some(unit) = detect_unit(name) {
+        writer.write_all(b"# UNIT ")?;
+        writer.write_all(name.as_bytes())?;
+        writer.write_all(b" ")?;
+        writer.write_all(unit.as_bytes())?;
+        writer.write_all(b"\n")?;
+    }
+
+    if !mf.get_help().is_empty() {
+        writer.write_all(b"# HELP ")?;
+        writer.write_all(name.as_bytes())?;
+        writer.write_all(b" ")?;
+        writer.write_all(escape_help(mf.get_help()).as_bytes())?;
+        writer.write_all(b"\n")?;
+    }
+
+    for m in mf.get_metric() {
+        write_metric(writer, name, metric_type, m)?;
+    }
+
+    Ok(())
+}
+
+fn write_metric(writer: &mut Vec<u8>, name: &str, metric_type: MetricType, m: &Metric) -> Result<()> {
+    match metric_type {
+        MetricType::COUNTER => {
+            write_sample(
+                writer,
+                &format!("{}{}", name, TOTAL_SUFFIX),
+                m.get_label(),
+                None,
+                m.get_counter().get_value(),
+            )?;
+        }
+        MetricType::GAUGE => {
+            write_sample(writer, name, m.get_label(), None, m.get_gauge().get_value())?;
+        }
+        MetricType::UNTYPED => {
+            write_sample(
+                writer,
+                name,
+                m.get_label(),
+                None,
+                m.get_untyped().get_value(),
+            )?;
+        }
+        MetricType::SUMMARY => {
+            let summary = m.get_summary();
+            for q in summary.get_quantile() {
+                write_sample(
+                    writer,
+                    name,
+                    m.get_label(),
+                    Some(("quantile", &format_float(q.get_quantile()))),
+                    q.get_value(),
+                )?;
+            }
+            write_sample(
+                writer,
+                &format!("{}_sum", name),
+                m.get_label(),
+                None,
+                summary.get_sample_sum(),
+            )?;
+            write_sample(
+                writer,
+                &format!("{}_count", name),
+                m.get_label(),
+                None,
+                summary.get_sample_count() as f64,
+            )?;
+        }
+        MetricType::HISTOGRAM => {
+            let histogram = m.get_histogram();
+            for b in histogram.get_bucket() {
+                write_sample(
+                    writer,
+                    &format!("{}_bucket", name),
+                    m.get_label(),
+                    Some(("le", &format_float(b.get_upper_bound()))),
+                    b.get_cumulative_count() as f64,
+                )?;
+            }
+            write_sample(
+                writer,
+                &format!("{}_bucket", name),
+                m.get_label(),
+                Some(("le", "+Inf")),
+                histogram.get_sample_count() as f64,
+            )?;
+            write_sample(
+                writer,
+                &format!("{}_sum", name),
+                m.get_label(),
+                None,
+                histogram.get_sample_sum(),
+            )?;
+            write_sample(
+                writer,
+                &format!("{}_count", name),
+                m.get_label(),
+                None,
+                histogram.get_sample_count() as f64,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_sample(
+    writer: &mut Vec<u8>,
+    name: &str,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, &str)>,
+    value: f64,
+) -> Result<()> {
+    writer.write_all(name.as_bytes())?;
+
+    if !labels.is_empty() || extra_label.is_some() {
+        writer.write_all(b"{")?;
+        let mut first = true;
+        for lp in labels {
+            if !first {
+                writer.write_all(b",")?;
+            }
+            first = false;
+            write_label_pair(writer, lp.get_name(), lp.get_value())?;
+        }
+        if let Some((name, value)) = extra_label {
+            if !first {
+                writer.write_all(b",")?;
+            }
+            write_label_pair(writer, name, value)?;
+        }
+        writer.write_all(b"}")?;
+    }
+
+    write!(writer, " {}\n", format_float(value))?;
+    Ok(())
+}
+
+// `format_float` renders a sample value per the OpenMetrics spec, which
+// mandates the `+Inf`/`-Inf`/`NaN` tokens instead of Rust's default `f64`
+// `Display` output (`inf`/`-inf`/`NaN`).
+fn format_float(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".to_owned()
+    } else if v == ::std::f64::INFINITY {
+        "+Inf".to_owned()
+    } else if v == ::std::f64::NEG_INFINITY {
+        "-Inf".to_owned()
+    } else {
+        v.to_string()
+    }
+}
+
+fn write_label_pair(writer: &mut Vec<u8>, name: &str, value: &str) -> Result<()> {
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(b"=\"")?;
+    writer.write_all(escape_label_value(value).as_bytes())?;
+    writer.write_all(b"\"")?;
+    Ok(())
+}
+
+fn strip_total_suffix(name: &str, metric_type: MetricType) -> &str {
+    if metric_type == MetricType::COUNTER && name.ends_with(TOTAL_SUFFIX) {
+        &name[..name.len() - TOTAL_SUFFIX.len()]
+    } else {
+        name
+    }
+}
+
+fn type_str(metric_type: MetricType) -> &'static str {
+    match metric_type {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::SUMMARY => "summary",
+        MetricType::HISTOGRAM => "histogram",
+        MetricType::UNTYPED => "unknown",
+    }
+}
+
+// `escape_label_value` escapes backslashes, double quotes and newlines in a
+// label value, per the OpenMetrics escaping rules (identical to the classic
+// Prometheus text format).
+fn escape_label_value(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('\"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// `escape_help` escapes backslashes and newlines in a HELP line.
+fn escape_help(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::{Counter as ProtoCounter, Gauge as ProtoGauge, LabelPair, Metric, MetricFamily,
+                MetricType};
+
+    fn encode(mfs: &[MetricFamily]) -> String {
+        let encoder = OpenMetricsEncoder::new();
+        let mut buf = Vec::new();
+        encoder.encode(mfs, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_counter_gets_total_suffix() {
+        let mut counter_value = ProtoCounter::new();
+        counter_value.set_value(42.0);
+        let mut metric = Metric::new();
+        metric.set_counter(counter_value);
+
+        let mut mf = MetricFamily::new();
+        mf.set_name("http_requests".to_owned());
+        mf.set_help("total requests".to_owned());
+        mf.set_field_type(MetricType::COUNTER);
+        mf.mut_metric().push(metric);
+
+        assert_eq!(
+            encode(&[mf]),
+            "# TYPE http_requests counter\n\
+             # HELP http_requests total requests\n\
+             http_requests_total 42\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn test_counter_strips_existing_total_suffix() {
+        let mut counter_value = ProtoCounter::new();
+        counter_value.set_value(1.0);
+        let mut metric = Metric::new();
+        metric.set_counter(counter_value);
+
+        let mut mf = MetricFamily::new();
+        mf.set_name("http_requests_total".to_owned());
+        mf.set_help(String::new());
+        mf.set_field_type(MetricType::COUNTER);
+        mf.mut_metric().push(metric);
+
+        assert_eq!(
+            encode(&[mf]),
+            "# TYPE http_requests counter\n\
+             http_requests_total 1\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn test_unit_line_is_inferred_from_name_suffix() {
+        let mut gauge_value = ProtoGauge::new();
+        gauge_value.set_value(2.5);
+        let mut metric = Metric::new();
+        metric.set_gauge(gauge_value);
+
+        let mut mf = MetricFamily::new();
+        mf.set_name("request_latency_seconds".to_owned());
+        mf.set_help("latency".to_owned());
+        mf.set_field_type(MetricType::GAUGE);
+        mf.mut_metric().push(metric);
+
+        assert_eq!(
+            encode(&[mf]),
+            "# TYPE request_latency_seconds gauge\n\
+             # UNIT request_latency_seconds seconds\n\
+             # HELP request_latency_seconds latency\n\
+             request_latency_seconds 2.5\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn test_infinite_and_nan_values_use_spec_tokens() {
+        let mut inf_value = ProtoGauge::new();
+        inf_value.set_value(::std::f64::INFINITY);
+        let mut inf_metric = Metric::new();
+        inf_metric.set_gauge(inf_value);
+        let mut inf_mf = MetricFamily::new();
+        inf_mf.set_name("saturated".to_owned());
+        inf_mf.set_help(String::new());
+        inf_mf.set_field_type(MetricType::GAUGE);
+        inf_mf.mut_metric().push(inf_metric);
+
+        let mut nan_value = ProtoGauge::new();
+        nan_value.set_value(::std::f64::NAN);
+        let mut nan_metric = Metric::new();
+        nan_metric.set_gauge(nan_value);
+        let mut nan_mf = MetricFamily::new();
+        nan_mf.set_name("undefined".to_owned());
+        nan_mf.set_help(String::new());
+        nan_mf.set_field_type(MetricType::GAUGE);
+        nan_mf.mut_metric().push(nan_metric);
+
+        let out = encode(&[inf_mf, nan_mf]);
+        assert!(out.contains("saturated +Inf\n"));
+        assert!(out.contains("undefined NaN\n"));
+    }
+
+    #[test]
+    fn test_label_values_are_escaped() {
+        let mut counter_value = ProtoCounter::new();
+        counter_value.set_value(1.0);
+        let mut lp = LabelPair::new();
+        lp.set_name("path".to_owned());
+        lp.set_value("a\"b\\c\nd".to_owned());
+        let mut metric = Metric::new();
+        metric.set_counter(counter_value);
+        metric.mut_label().push(lp);
+
+        let mut mf = MetricFamily::new();
+        mf.set_name("hits".to_owned());
+        mf.set_help(String::new());
+        mf.set_field_type(MetricType::COUNTER);
+        mf.mut_metric().push(metric);
+
+        let out = encode(&[mf]);
+        assert!(out.contains("hits_total{path=\"a\\\"b\\\\c\\nd\"} 1\n"));
+    }
+}
@@ -23,10 +23,18 @@ use std::collections::hash_map::Entry as HEntry;
 use std::iter::FromIterator;
 use std::sync::Arc;
 
+/// A `Transform` is run over the fully merged and sorted set of
+/// `MetricFamily`s gathered by a `Registry`, and may add, remove or rewrite
+/// entries in place before they are handed to the caller.
+pub type Transform = Box<Fn(&mut Vec<proto::MetricFamily>) + Send + Sync>;
+
 struct RegistryCore {
     pub colloctors_by_id: HashMap<u64, Box<Collector>>,
     pub dim_hashes_by_name: HashMap<String, u64>,
     pub desc_ids: HashSet<u64>,
+    pub prefix: Option<String>,
+    pub labels: Option<HashMap<String, String>>,
+    pub transforms: Vec<Transform>,
 }
 
 impl RegistryCore {
@@ -109,72 +117,232 @@ impl RegistryCore {
     }
 
     fn gather(&self) -> Vec<proto::MetricFamily> {
-        let mut mf_by_name = BTreeMap::new();
+        match self.gather_internal() {
+            Ok(mfs) => mfs,
+            Err((mfs, errors)) => {
+                for err in &errors {
+                    warn!("{}", err);
+                }
+                mfs
+            }
+        }
+    }
+
+    fn gather_checked(&self) -> Result<Vec<proto::MetricFamily>> {
+        match self.gather_internal() {
+            Ok(mfs) => Ok(mfs),
+            Err((_, mut errors)) => Err(errors.remove(0)),
+        }
+    }
+
+    // `gather_internal` merges and sorts the collected MetricFamilies the
+    // same way `gather` always has, but additionally checks that every
+    // family is internally consistent (same `MetricType` from every
+    // collector contributing to it, no two `Metric`s sharing the same
+    // label-value tuple). On success it returns the merged families; on
+    // failure it returns the families with the offending ones removed,
+    // alongside every consistency error found, so callers can choose to
+    // fail fast (`gather_checked`) or degrade gracefully (`gather`).
+    fn gather_internal(
+        &self,
+    ) -> ::std::result::Result<Vec<proto::MetricFamily>, (Vec<proto::MetricFamily>, Vec<Error>)> {
+        let mut collected = Vec::new();
 
         for c in self.colloctors_by_id.values() {
             let mfs = c.collect();
             for mut mf in mfs {
-                let name = mf.get_name().to_owned();
+                if let Some(ref prefix) = self.prefix {
+                    let name = format!("{}{}", prefix, mf.get_name());
+                    mf.set_name(name);
+                }
 
-                match mf_by_name.entry(name) {
-                    BEntry::Vacant(entry) => {
-                        entry.insert(mf);
-                    }
-                    BEntry::Occupied(mut entry) => {
-                        let existent_mf = entry.get_mut();
-                        let existent_metrics = existent_mf.mut_metric();
-
-                        // TODO: check type.
-                        // TODO: check consistency.
-                        for metric in mf.take_metric().into_iter() {
-                            existent_metrics.push(metric);
-                        }
+                if let Some(ref labels) = self.labels {
+                    for metric in mf.mut_metric().iter_mut() {
+                        inject_labels(metric, labels);
                     }
                 }
+
+                collected.push(mf);
+            }
+        }
+
+        let (mut mfs, mut errors) = match merge_metric_families(collected) {
+            Ok(mfs) => (mfs, Vec::new()),
+            Err((mfs, errors)) => (mfs, errors),
+        };
+
+        // Run the registered transforms, then re-merge: a transform may have
+        // renamed a family into collision with another, or injected a
+        // synthetic family under an existing name, so we run the families
+        // back through `merge_metric_families` rather than just re-sorting,
+        // to avoid emitting two MetricFamily entries with the same name.
+        for t in &self.transforms {
+            t(&mut mfs);
+        }
+        match merge_metric_families(mfs) {
+            Ok(merged) => mfs = merged,
+            Err((merged, merge_errors)) => {
+                mfs = merged;
+                errors.extend(merge_errors);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(mfs)
+        } else {
+            Err((mfs, errors))
+        }
+    }
+}
+
+// `sort_metrics_by_label_values` sorts a MetricFamily's Metrics
+// lexicographically by their label values, as required by the exposition
+// formats.
+fn sort_metrics_by_label_values(mf: &mut proto::MetricFamily) {
+    mf.mut_metric().sort_by(|m1, m2| {
+        let lps1 = m1.get_label();
+        let lps2 = m2.get_label();
+
+        if lps1.len() != lps2.len() {
+            // This should not happen. The metrics are
+            // inconsistent. However, we have to deal with the fact, as
+            // people might use custom collectors or metric family injection
+            // to create inconsistent metrics. So let's simply compare the
+            // number of labels in this case. That will still yield
+            // reproducible sorting.
+            return lps1.len().cmp(&lps2.len());
+        }
+
+        for (lp1, lp2) in lps1.iter().zip(lps2.iter()) {
+            if lp1.get_value() != lp2.get_value() {
+                return lp1.get_value().cmp(lp2.get_value());
             }
         }
 
-        // TODO: metric_family injection hook.
-
-        // Now that MetricFamilies are all set, sort their Metrics
-        // lexicographically by their label values.
-        for mf in mf_by_name.values_mut() {
-            mf.mut_metric().sort_by(|m1, m2| {
-                let lps1 = m1.get_label();
-                let lps2 = m2.get_label();
-
-                if lps1.len() != lps2.len() {
-                    // This should not happen. The metrics are
-                    // inconsistent. However, we have to deal with the fact, as
-                    // people might use custom collectors or metric family injection
-                    // to create inconsistent metrics. So let's simply compare the
-                    // number of labels in this case. That will still yield
-                    // reproducible sorting.
-                    return lps1.len().cmp(&lps2.len());
+        // We should never arrive here. Multiple metrics with the same
+        // label set in the same scrape will lead to undefined ingestion
+        // behavior. However, as above, we have to provide stable sorting
+        // here, even for inconsistent metrics. So sort equal metrics
+        // by their timestamp, with missing timestamps (implying "now")
+        // coming last.
+        m1.get_timestamp_ms().cmp(&m2.get_timestamp_ms())
+    });
+}
+
+// `merge_metric_families` merges MetricFamilies sharing a name into one,
+// sorts the result lexicographically by name, and checks each merged family
+// for internal consistency (same `MetricType`, no duplicate label-value
+// tuples). It underlies both a single `Registry`'s `gather`/`gather_checked`
+// and `Gatherers`, which federates several `Registry`s through the same
+// merge/dedup path.
+fn merge_metric_families(
+    mfs: Vec<proto::MetricFamily>,
+) -> ::std::result::Result<Vec<proto::MetricFamily>, (Vec<proto::MetricFamily>, Vec<Error>)> {
+    let mut mf_by_name = BTreeMap::new();
+    let mut type_by_name: HashMap<String, proto::MetricType> = HashMap::new();
+    let mut errors = Vec::new();
+    let mut bad_names = HashSet::new();
+
+    for mut mf in mfs {
+        let name = mf.get_name().to_owned();
+
+        match type_by_name.entry(name.clone()) {
+            HEntry::Vacant(entry) => {
+                entry.insert(mf.get_field_type());
+            }
+            HEntry::Occupied(entry) => {
+                if *entry.get() != mf.get_field_type() {
+                    errors.push(Error::Msg(format!(
+                        "collected metric \"{}\" has inconsistent type: \
+                         expected {:?}, got {:?}",
+                        name,
+                        entry.get(),
+                        mf.get_field_type()
+                    )));
+                    bad_names.insert(name.clone());
                 }
+            }
+        }
 
-                for (lp1, lp2) in lps1.iter().zip(lps2.iter()) {
-                    if lp1.get_value() != lp2.get_value() {
-                        return lp1.get_value().cmp(lp2.get_value());
-                    }
+        match mf_by_name.entry(name) {
+            BEntry::Vacant(entry) => {
+                entry.insert(mf);
+            }
+            BEntry::Occupied(mut entry) => {
+                let existent_mf = entry.get_mut();
+                let existent_metrics = existent_mf.mut_metric();
+
+                for metric in mf.take_metric().into_iter() {
+                    existent_metrics.push(metric);
                 }
+            }
+        }
+    }
 
-                // We should never arrive here. Multiple metrics with the same
-                // label set in the same scrape will lead to undefined ingestion
-                // behavior. However, as above, we have to provide stable sorting
-                // here, even for inconsistent metrics. So sort equal metrics
-                // by their timestamp, with missing timestamps (implying "now")
-                // coming last.
-                m1.get_timestamp_ms().cmp(&m2.get_timestamp_ms())
-            });
+    // Now that MetricFamilies are all set, sort their Metrics
+    // lexicographically by their label values.
+    for (name, mf) in mf_by_name.iter_mut() {
+        sort_metrics_by_label_values(mf);
+
+        if mf.get_metric()
+            .windows(2)
+            .any(|w| label_values(&w[0]) == label_values(&w[1]))
+        {
+            errors.push(Error::Msg(format!(
+                "collected metric family \"{}\" has duplicate metrics \
+                 with identical label values",
+                name
+            )));
+            bad_names.insert(name.clone());
         }
+    }
 
-        // Write out MetricFamilies sorted by their name.
-        let kvs = Vec::from_iter(mf_by_name.into_iter());
-        kvs.into_iter().map(|(_, m)| m).collect()
+    // Write out MetricFamilies sorted by their name.
+    let kvs = Vec::from_iter(mf_by_name.into_iter());
+    let mfs: Vec<_> = kvs.into_iter().map(|(_, m)| m).collect();
+
+    if errors.is_empty() {
+        Ok(mfs)
+    } else {
+        let filtered = mfs.into_iter()
+            .filter(|mf| !bad_names.contains(mf.get_name()))
+            .collect();
+        Err((filtered, errors))
     }
 }
 
+// `label_values` returns a metric's label values, in the order they are
+// stored, for use as a duplicate-series key.
+fn label_values(m: &proto::Metric) -> Vec<&str> {
+    m.get_label().iter().map(|lp| lp.get_value()).collect()
+}
+
+// `inject_labels` merges `labels` into `metric`'s label set, skipping any
+// label name the metric already defines so per-metric labels take
+// precedence, and keeps the label set in sorted order.
+fn inject_labels(metric: &mut proto::Metric, labels: &HashMap<String, String>) {
+    let existing: HashSet<&str> = metric
+        .get_label()
+        .iter()
+        .map(|lp| lp.get_name())
+        .collect();
+
+    for (name, value) in labels {
+        if existing.contains(name.as_str()) {
+            continue;
+        }
+
+        let mut lp = proto::LabelPair::new();
+        lp.set_name(name.clone());
+        lp.set_value(value.clone());
+        metric.mut_label().push(lp);
+    }
+
+    metric
+        .mut_label()
+        .sort_by(|a, b| a.get_name().cmp(b.get_name()));
+}
+
 /// `Registry` registers Prometheus collectors, collects their metrics, and gathers
 /// them into `MetricFamilies` for exposition.
 #[derive(Clone)]
@@ -184,23 +352,36 @@ pub struct Registry {
 
 impl Default for Registry {
     fn default() -> Registry {
+        Registry::new_custom(None, None)
+    }
+}
+
+impl Registry {
+    /// `new` creates a Registry.
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// `new_custom` creates a Registry with the given `prefix` and `labels`.
+    /// The `prefix` is prepended to the name of every `MetricFamily` collected
+    /// from the registered Collectors, and `labels` are merged into the labels
+    /// of each of their `Metric`s, with a metric's own labels taking
+    /// precedence over colliding label names. Families added by a `Transform`
+    /// get neither the prefix nor the labels.
+    pub fn new_custom(prefix: Option<String>, labels: Option<HashMap<String, String>>) -> Registry {
         let r = RegistryCore {
             colloctors_by_id: HashMap::new(),
             dim_hashes_by_name: HashMap::new(),
             desc_ids: HashSet::new(),
+            prefix: prefix,
+            labels: labels,
+            transforms: Vec::new(),
         };
 
         Registry {
             r: Arc::new(RwLock::new(r)),
         }
     }
-}
-
-impl Registry {
-    /// `new` creates a Registry.
-    pub fn new() -> Registry {
-        Registry::default()
-    }
 
     /// `register` registers a new Collector to be included in metrics
     /// collection. It returns an error if the descriptors provided by the
@@ -229,6 +410,66 @@ impl Registry {
     pub fn gather(&self) -> Vec<proto::MetricFamily> {
         self.r.read().gather()
     }
+
+    /// `gather_checked` behaves like `gather`, but fails fast instead of
+    /// silently merging inconsistent data: it returns an error as soon as two
+    /// collected families sharing a name disagree on `MetricType`, or two
+    /// `Metric`s within a merged family end up with identical label values.
+    pub fn gather_checked(&self) -> Result<Vec<proto::MetricFamily>> {
+        self.r.read().gather_checked()
+    }
+
+    /// `register_transform` registers a `Transform` to run over the
+    /// assembled `MetricFamily`s at the end of every `gather`/`gather_checked`
+    /// call, after collectors are merged and consistency-sorted. Transforms
+    /// run in registration order and may inject synthetic families (e.g. a
+    /// build-info metric), drop or rename families, or rewrite labels at
+    /// scrape time. The family-name and per-metric label sort is re-applied
+    /// after all transforms run, so injected data stays ordered.
+    pub fn register_transform(&self, t: Transform) {
+        self.r.write().transforms.push(t);
+    }
+}
+
+/// `Gather` types can be gathered into a lexicographically sorted slice of
+/// `MetricFamily` protobufs. `Registry` implements it directly; `Gatherers`
+/// implements it by federating several `Gather`s together.
+pub trait Gather {
+    /// `gather` collects the `MetricFamily` protobufs exposed by this type.
+    fn gather(&self) -> Vec<proto::MetricFamily>;
+}
+
+impl Gather for Registry {
+    fn gather(&self) -> Vec<proto::MetricFamily> {
+        Registry::gather(self)
+    }
+}
+
+/// `Gatherers` federates several `Gather`s — typically a process-local
+/// `Registry` alongside one or more library-owned `Registry`s — into a
+/// single lexicographically sorted slice of `MetricFamily` protobufs,
+/// merging and de-duplicating families that share a name across the
+/// underlying `Gather`s the same way a single `Registry::gather` does.
+/// This mirrors the multi-gatherer support in the Prometheus Go client.
+pub struct Gatherers(pub Vec<Box<Gather>>);
+
+impl Gatherers {
+    /// `gather` calls `gather` on every underlying `Gather` and merges the
+    /// results into one lexicographically sorted slice of MetricFamily
+    /// protobufs.
+    pub fn gather(&self) -> Vec<proto::MetricFamily> {
+        let mfs = self.0.iter().flat_map(|g| g.gather()).collect();
+
+        match merge_metric_families(mfs) {
+            Ok(mfs) => mfs,
+            Err((mfs, errors)) => {
+                for err in &errors {
+                    warn!("{}", err);
+                }
+                mfs
+            }
+        }
+    }
 }
 
 cfg_if! {
@@ -405,6 +646,231 @@ mod tests {
         assert_eq!(ms[3].get_counter().get_value() as u64, 4);
     }
 
+    #[test]
+    fn test_registry_with_prefix_and_labels() {
+        let mut labels = HashMap::new();
+        labels.insert("a".to_owned(), "1".to_owned());
+        labels.insert("b".to_owned(), "2".to_owned());
+        let r = Registry::new_custom(Some("pre_".to_owned()), Some(labels));
+
+        let counter = Counter::new("test", "test help").unwrap();
+        r.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+
+        let counter_vec =
+            CounterVec::new(Opts::new("test_vec", "test vec help").const_label("a", "3"), &["c"])
+                .unwrap();
+        r.register(Box::new(counter_vec.clone())).unwrap();
+        counter_vec.with_label_values(&["1"]).inc();
+
+        let mfs = r.gather();
+        assert_eq!(mfs.len(), 2);
+
+        let plain = mfs.iter().find(|mf| mf.get_name() == "pre_test").unwrap();
+        let labels = plain.get_metric()[0].get_label();
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[0].get_name(), "a");
+        assert_eq!(labels[0].get_value(), "1");
+        assert_eq!(labels[1].get_name(), "b");
+        assert_eq!(labels[1].get_value(), "2");
+
+        let with_own = mfs
+            .iter()
+            .find(|mf| mf.get_name() == "pre_test_vec")
+            .unwrap();
+        let labels = with_own.get_metric()[0].get_label();
+        assert_eq!(labels.len(), 3);
+        assert_eq!(labels[0].get_name(), "a");
+        assert_eq!(labels[0].get_value(), "3");
+        assert_eq!(labels[1].get_name(), "b");
+        assert_eq!(labels[1].get_value(), "2");
+        assert_eq!(labels[2].get_name(), "c");
+        assert_eq!(labels[2].get_value(), "1");
+    }
+
+    #[test]
+    fn test_gather_checked() {
+        use proto::{Counter as ProtoCounter, Gauge as ProtoGauge, Metric, MetricFamily, MetricType};
+
+        struct InconsistentCollector {
+            desc: Desc,
+        }
+
+        impl Collector for InconsistentCollector {
+            fn desc(&self) -> Vec<&Desc> {
+                vec![&self.desc]
+            }
+
+            fn collect(&self) -> Vec<MetricFamily> {
+                let mut counter_value = ProtoCounter::new();
+                counter_value.set_value(1.0);
+                let mut counter_metric = Metric::new();
+                counter_metric.set_counter(counter_value);
+                let mut counter_mf = MetricFamily::new();
+                counter_mf.set_name("inconsistent".to_owned());
+                counter_mf.set_help("inconsistent help".to_owned());
+                counter_mf.set_field_type(MetricType::COUNTER);
+                counter_mf.mut_metric().push(counter_metric);
+
+                let mut gauge_value = ProtoGauge::new();
+                gauge_value.set_value(2.0);
+                let mut gauge_metric = Metric::new();
+                gauge_metric.set_gauge(gauge_value);
+                let mut gauge_mf = MetricFamily::new();
+                gauge_mf.set_name("inconsistent".to_owned());
+                gauge_mf.set_help("inconsistent help".to_owned());
+                gauge_mf.set_field_type(MetricType::GAUGE);
+                gauge_mf.mut_metric().push(gauge_metric);
+
+                vec![counter_mf, gauge_mf]
+            }
+        }
+
+        let r = Registry::new();
+        let desc = Desc::new(
+            "inconsistent".to_owned(),
+            "inconsistent help".to_owned(),
+            vec![],
+            HashMap::new(),
+        ).unwrap();
+        r.register(Box::new(InconsistentCollector { desc: desc }))
+            .unwrap();
+
+        // The checked path fails fast on the type mismatch between the two
+        // MetricFamilies sharing the "inconsistent" name.
+        assert!(r.gather_checked().is_err());
+
+        // The best-effort path logs the same error but still returns the
+        // families that were not affected by it; here that means none.
+        assert_eq!(r.gather().len(), 0);
+    }
+
+    #[test]
+    fn test_register_transform() {
+        let r = Registry::new();
+
+        let counter = Counter::new("test_transform", "test help").unwrap();
+        r.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+
+        r.register_transform(Box::new(|mfs: &mut Vec<proto::MetricFamily>| {
+            let mut build_info = proto::MetricFamily::new();
+            build_info.set_name("build_info".to_owned());
+            build_info.set_help("build information".to_owned());
+            build_info.set_field_type(proto::MetricType::GAUGE);
+
+            let mut gauge = proto::Gauge::new();
+            gauge.set_value(1.0);
+            let mut metric = proto::Metric::new();
+            metric.set_gauge(gauge);
+            build_info.mut_metric().push(metric);
+
+            mfs.push(build_info);
+        }));
+
+        let mfs = r.gather();
+        assert_eq!(mfs.len(), 2);
+        assert_eq!(mfs[0].get_name(), "build_info");
+        assert_eq!(mfs[1].get_name(), "test_transform");
+    }
+
+    #[test]
+    fn test_transform_rename_collision_is_merged() {
+        let r = Registry::new();
+
+        // Distinct label values, so the merged family holds two separate
+        // series rather than a duplicate that the consistency check drops.
+        let counter_a =
+            CounterVec::new(Opts::new("test_a_counter", "test help"), &["src"]).unwrap();
+        r.register(Box::new(counter_a.clone())).unwrap();
+        counter_a.with_label_values(&["a"]).inc();
+
+        let counter_b =
+            CounterVec::new(Opts::new("test_b_counter", "test help"), &["src"]).unwrap();
+        r.register(Box::new(counter_b.clone())).unwrap();
+        counter_b.with_label_values(&["b"]).inc();
+        counter_b.with_label_values(&["b"]).inc();
+
+        // Rename "test_b_counter" into "test_a_counter", colliding with an
+        // already-gathered family of that name.
+        r.register_transform(Box::new(|mfs: &mut Vec<proto::MetricFamily>| {
+            for mf in mfs.iter_mut() {
+                if mf.get_name() == "test_b_counter" {
+                    mf.set_name("test_a_counter".to_owned());
+                }
+            }
+        }));
+
+        let mfs = r.gather();
+
+        // The renamed family must be merged into the existing one instead of
+        // appearing as a second MetricFamily with the same name.
+        assert_eq!(mfs.len(), 1);
+        assert_eq!(mfs[0].get_name(), "test_a_counter");
+        assert_eq!(mfs[0].get_metric().len(), 2);
+    }
+
+    #[test]
+    fn test_gatherers() {
+        let r1 = Registry::new();
+        let counter_a = Counter::new("test_a_counter", "test help").unwrap();
+        r1.register(Box::new(counter_a.clone())).unwrap();
+        counter_a.inc();
+
+        let r2 = Registry::new();
+        let counter_b = Counter::new("test_b_counter", "test help").unwrap();
+        r2.register(Box::new(counter_b.clone())).unwrap();
+        counter_b.inc();
+        counter_b.inc();
+
+        let gatherers = Gatherers(vec![Box::new(r1), Box::new(r2)]);
+        let mfs = gatherers.gather();
+
+        assert_eq!(mfs.len(), 2);
+        assert_eq!(mfs[0].get_name(), "test_a_counter");
+        assert_eq!(mfs[1].get_name(), "test_b_counter");
+        assert_eq!(mfs[1].get_metric()[0].get_counter().get_value() as u64, 2);
+    }
+
+    #[test]
+    fn test_gatherers_merge_same_name_family() {
+        let r1 = Registry::new();
+        let shared_1 = CounterVec::new(Opts::new("shared_counter", "shared help"), &["instance"])
+            .unwrap();
+        r1.register(Box::new(shared_1.clone())).unwrap();
+        shared_1.with_label_values(&["one"]).inc();
+
+        let r2 = Registry::new();
+        let shared_2 = CounterVec::new(Opts::new("shared_counter", "shared help"), &["instance"])
+            .unwrap();
+        r2.register(Box::new(shared_2.clone())).unwrap();
+        shared_2.with_label_values(&["two"]).inc();
+        shared_2.with_label_values(&["two"]).inc();
+
+        let gatherers = Gatherers(vec![Box::new(r1.clone()), Box::new(r2.clone())]);
+        let mfs = gatherers.gather();
+
+        // Both registries contribute to the same family name, so the metrics
+        // must be concatenated into a single MetricFamily.
+        assert_eq!(mfs.len(), 1);
+        assert_eq!(mfs[0].get_name(), "shared_counter");
+        let metrics = mfs[0].get_metric();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0].get_label()[0].get_value(), "one");
+        assert_eq!(metrics[0].get_counter().get_value() as u64, 1);
+        assert_eq!(metrics[1].get_label()[0].get_value(), "two");
+        assert_eq!(metrics[1].get_counter().get_value() as u64, 2);
+
+        // Registering a metric with the same label values on both
+        // registries produces a duplicate series once merged; the
+        // best-effort `Gatherers::gather` must drop the offending family
+        // rather than expose the inconsistency, the same way
+        // `Registry::gather` does.
+        shared_1.with_label_values(&["two"]).inc();
+        let mfs = gatherers.gather();
+        assert!(mfs.iter().all(|mf| mf.get_name() != "shared_counter"));
+    }
+
     struct MultipleCollector {
         descs: Vec<Desc>,
         counters: Vec<Counter>,